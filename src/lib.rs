@@ -3,68 +3,573 @@
 //! Macro to create a "view box", a box containing data plus
 //! a "view" struct that can have interior references into the data.
 //! The view box be can moved around as an atomic unit.
+//!
+//! By default the generated box is drop-safe: the view is torn down
+//! before the data it borrows from, regardless of the field-drop
+//! order the compiler happens to pick, so a `Drop` impl on the view
+//! type can never observe a dangling reference. Invocations that
+//! know their view has no `Drop` impl of its own can opt out of the
+//! extra `Option` discriminant with `#[derive(Drop)]` (see below).
+//!
+//! The data is held through a `NonNull<$d>` rather than a `Box<$d>`
+//! field. A `Box<$d>` field would carry LLVM's `noalias` attribute on
+//! its pointee, which conflicts with `view` holding live references
+//! into that very allocation; `NonNull` carries no such attribute, so
+//! the aliasing is no longer a miscompilation hazard. `$name` is
+//! `!Unpin` and `new`/`new_result` hand back `Pin<Box<$name>>` so the
+//! allocation backing `view`'s references can never be moved out from
+//! under them while they exist.
+//!
+//! The box is write-once only in the sense that you can't grow a new
+//! view out of thin air: `map_view`/`try_map_view` re-derive the view
+//! from the *current* view (never `data` directly), so you can narrow
+//! an existing view down to a sub-node any number of times without
+//! reallocating, and `with_owner` lets you peek at `data` without
+//! giving up the view via `into_inner`. The new view doesn't have to
+//! be the same type as the old one: `map_view`/`try_map_view` are
+//! generic over the *target* box (any other non-`shared`
+//! `viewbox!`-generated box sharing the same `$d`), so narrowing into a
+//! sub-node can land in a box carrying a different, narrower view type.
+//!
+//! `viewbox!(shared struct $name<$d,$v>;)` is a second entry point
+//! that holds `data` in an `Arc` instead of a `Box`, so the box can
+//! be cheaply cloned to share one allocation between multiple owners,
+//! each with their own `view` into it.
+//!
+//! `$name`, `$d` and `$v` can also be generic:
+//! `viewbox!(struct $name<$($g),*; $d, $v> where $($wt: $wb),*;)` threads
+//! `$($g),*` through the generated struct, impl and `$v<'static, ...>`
+//! instantiation, so e.g. a `Vec<T>` or a `&'env [u8]` can be
+//! viewboxed without hand-writing the transmute boilerplate per type.
+
+/// Internal raw constructor/destructor pair implemented by every
+/// `viewbox!`-generated box except the `shared` one, letting `map_view`/`try_map_view` hand
+/// `data` off to a *different* box type (one with a different view)
+/// without reallocating. Not meant to be implemented by hand; hidden
+/// from docs since it's plumbing, not part of the public API surface.
+#[doc(hidden)]
+pub trait ViewBoxRaw: Sized {
+    type Data;
+    type View<'a>;
+
+    #[doc(hidden)]
+    unsafe fn __from_raw(data: ::std::ptr::NonNull<Self::Data>, view: Self::View<'static>)
+                         -> ::std::pin::Pin<Box<Self>>;
+    #[doc(hidden)]
+    unsafe fn __into_raw(self: ::std::pin::Pin<Box<Self>>)
+                         -> (::std::ptr::NonNull<Self::Data>, Self::View<'static>);
+}
 
 #[macro_export]
 macro_rules! viewbox {
+    // Generic form: `$d`/`$v` may mention the struct's own generic
+    // parameters (lifetimes or type params), e.g. viewboxing a
+    // `Vec<T>` or a `&'env [u8]`. The parameters themselves stay
+    // fixed on the impl (they're not part of the `new`/`new_result`
+    // higher-ranked bound) -- only the view's own borrow of `data`,
+    // `'a`, is quantified over. Each where-predicate takes a single
+    // token as its bound (`T: Clone`, `T: 'static`), not a
+    // multi-bound or multi-segment path (`T: Bound1 + Bound2`).
+    //
+    // These two arms must come before the plain 2-param arm below:
+    // when `$($g),*` starts with a lifetime (e.g. `<'env, T; ...>`),
+    // the plain arm's `$d:ty` fragment would start parsing at the
+    // bare lifetime token and hard-fail the whole macro invocation
+    // instead of just failing to match, so it never gets a chance to
+    // fall through if tried first.
+    (struct $name:ident<$($g:tt),*; $d:ty, $v:ident> where $($wt:ty : $wb:tt),*;) => (
+        viewbox!(@generic struct $name<$($g),*; $d, $v> where $($wt : $wb),*;);
+    );
+    (struct $name:ident<$($g:tt),*; $d:ty, $v:ident>;) => (
+        viewbox!(@generic struct $name<$($g),*; $d, $v> where ;);
+    );
     (struct $name:ident<$d:ty, $v:ident>;) => (
         pub struct $name {
-            view: $v<'static>,
+            view: Option<$v<'static>>,
             #[allow(dead_code)]
-            data: Box<$d>
+            data: ::std::ptr::NonNull<$d>,
+            _pin: ::std::marker::PhantomPinned
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                // Drop the view (and anything it borrows back into
+                // `data`) before `data` itself is dropped below.
+                self.view.take();
+                unsafe { drop(Box::from_raw(self.data.as_ptr())); }
+            }
+        }
+
+        impl $crate::ViewBoxRaw for $name {
+            type Data = $d;
+            type View<'a> = $v<'a>;
+
+            unsafe fn __from_raw(data: ::std::ptr::NonNull<$d>, view: $v<'static>)
+                                 -> ::std::pin::Pin<Box<$name>> {
+                Box::pin($name { data, view: Some(view), _pin: ::std::marker::PhantomPinned })
+            }
+
+            unsafe fn __into_raw(self: ::std::pin::Pin<Box<$name>>)
+                                 -> (::std::ptr::NonNull<$d>, $v<'static>) {
+                let mut this = ::std::pin::Pin::into_inner_unchecked(self);
+                let view = this.view.take().unwrap();
+                let data = this.data;
+                ::std::mem::forget(this);
+                (data, view)
+            }
         }
 
         #[allow(dead_code)]
         impl $name {
-            pub fn new<F>(data: $d, f: F) -> $name
+            pub fn new<F>(data: $d, f: F) -> ::std::pin::Pin<Box<$name>>
                           where F: for<'a> FnOnce(&'a mut $d) -> $v<'a> {
-                let mut d = box data;
+                let mut d = Box::new(data);
                 let v = unsafe { ::std::mem::transmute(f(&mut *d)) };
-                
-                $name { data: d, view: v }
+                let ptr = unsafe { ::std::ptr::NonNull::new_unchecked(Box::into_raw(d)) };
+
+                Box::pin($name { data: ptr, view: Some(v), _pin: ::std::marker::PhantomPinned })
             }
 
             pub fn new_result<E,F>(data: $d,
                                    f: F)
-                                   -> ::std::result::Result<$name,($d,E)>
+                                   -> ::std::result::Result<::std::pin::Pin<Box<$name>>,($d,E)>
                                    where F: for<'a> FnOnce(&'a mut $d) -> ::std::result::Result<$v<'a>,E> {
-                let mut d = box data;
+                let mut d = Box::new(data);
                 match f(&mut *d).map(|v| unsafe { ::std::mem::transmute(v) }) {
-                    Ok(v) => Ok($name { data: d, view: v }),
+                    Ok(v) => {
+                        let ptr = unsafe { ::std::ptr::NonNull::new_unchecked(Box::into_raw(d)) };
+                        Ok(Box::pin($name { data: ptr, view: Some(v), _pin: ::std::marker::PhantomPinned }))
+                    }
                     Err(e) => Err((*d,e))
                 }
             }
-            
-            pub fn into_inner(self)-> $d {
-                let $name { data: box data, .. } = self;
-                data
+
+            pub fn into_inner(self: ::std::pin::Pin<Box<$name>>) -> $d {
+                unsafe {
+                    let mut this = ::std::pin::Pin::into_inner_unchecked(self);
+                    this.view.take();
+                    let ptr = this.data;
+                    ::std::mem::forget(this);
+                    *Box::from_raw(ptr.as_ptr())
+                }
+            }
+
+            pub fn view<'a>(self: ::std::pin::Pin<&'a $name>) -> &'a $v<'a> {
+                unsafe { ::std::mem::transmute(self.get_ref().view.as_ref().unwrap()) }
+            }
+
+            pub fn view_mut<'a>(self: ::std::pin::Pin<&'a mut $name>) -> &'a mut $v<'a> {
+                unsafe { ::std::mem::transmute(self.get_unchecked_mut().view.as_mut().unwrap()) }
+            }
+
+            /// Re-derives the view over the same live allocation,
+            /// without reparsing or reallocating `data`. `f` only
+            /// ever sees the current view, never `data` itself, so
+            /// it can narrow (e.g. to a sub-node) but not fabricate
+            /// references to data it was never given. The target box
+            /// `B` doesn't have to be `$name` itself -- it can be any
+            /// other non-`shared` `viewbox!`-generated box over the same `$d`, so
+            /// narrowing can land in a box with a different view type.
+            pub fn map_view<B, F>(self: ::std::pin::Pin<Box<$name>>, f: F)
+                                  -> ::std::pin::Pin<Box<B>>
+                                  where B: $crate::ViewBoxRaw<Data = $d>,
+                                        F: for<'a> FnOnce($v<'a>) -> B::View<'a> {
+                unsafe {
+                    let (data, old) = <$name as $crate::ViewBoxRaw>::__into_raw(self);
+                    // `f`'s `for<'a>` bound lets us call it at `'a =
+                    // 'static` directly, so the erased view never
+                    // needs an explicit lifetime transmute going in
+                    // or out.
+                    B::__from_raw(data, f(old))
+                }
+            }
+
+            /// Fallible counterpart of [`map_view`][Self::map_view].
+            /// Since `f` consumes the view, it must hand the view
+            /// back alongside `E` if it fails, so the original box
+            /// can be reconstituted for the caller.
+            pub fn try_map_view<B, E, F>(self: ::std::pin::Pin<Box<$name>>, f: F)
+                                        -> ::std::result::Result<::std::pin::Pin<Box<B>>,
+                                                                  (::std::pin::Pin<Box<$name>>,E)>
+                                        where B: $crate::ViewBoxRaw<Data = $d>,
+                                              F: for<'a> FnOnce($v<'a>)
+                                                 -> ::std::result::Result<B::View<'a>,($v<'a>,E)> {
+                unsafe {
+                    let (data, old) = <$name as $crate::ViewBoxRaw>::__into_raw(self);
+                    match f(old) {
+                        Ok(new) => Ok(B::__from_raw(data, new)),
+                        Err((old,e)) => Err((<$name as $crate::ViewBoxRaw>::__from_raw(data, old), e))
+                    }
+                }
+            }
+
+            /// Read-only access to the backing data, without having
+            /// to `into_inner` the box (and thus give up the view).
+            pub fn with_owner<R>(&self, f: impl FnOnce(&$d) -> R) -> R {
+                f(unsafe { self.data.as_ref() })
+            }
+        }
+    );
+    (@generic struct $name:ident<$($g:tt),*; $d:ty, $v:ident> where $($wt:ty : $wb:tt),*;) => (
+        pub struct $name<$($g),*> where $($wt : $wb),* {
+            view: Option<$v<'static, $($g),*>>,
+            #[allow(dead_code)]
+            data: ::std::ptr::NonNull<$d>,
+            _pin: ::std::marker::PhantomPinned
+        }
+
+        impl<$($g),*> Drop for $name<$($g),*> where $($wt : $wb),* {
+            fn drop(&mut self) {
+                // Drop the view (and anything it borrows back into
+                // `data`) before `data` itself is dropped below.
+                self.view.take();
+                unsafe { drop(Box::from_raw(self.data.as_ptr())); }
+            }
+        }
+
+        impl<$($g),*> $crate::ViewBoxRaw for $name<$($g),*> where $($wt : $wb),* {
+            type Data = $d;
+            type View<'a> = $v<'a, $($g),*>;
+
+            unsafe fn __from_raw(data: ::std::ptr::NonNull<$d>, view: $v<'static, $($g),*>)
+                                 -> ::std::pin::Pin<Box<$name<$($g),*>>> {
+                Box::pin($name { data, view: Some(view), _pin: ::std::marker::PhantomPinned })
+            }
+
+            unsafe fn __into_raw(self: ::std::pin::Pin<Box<$name<$($g),*>>>)
+                                 -> (::std::ptr::NonNull<$d>, $v<'static, $($g),*>) {
+                let mut this = ::std::pin::Pin::into_inner_unchecked(self);
+                let view = this.view.take().unwrap();
+                let data = this.data;
+                ::std::mem::forget(this);
+                (data, view)
+            }
+        }
+
+        #[allow(dead_code)]
+        impl<$($g),*> $name<$($g),*> where $($wt : $wb),* {
+            pub fn new<F>(data: $d, f: F) -> ::std::pin::Pin<Box<$name<$($g),*>>>
+                          where F: for<'a> FnOnce(&'a mut $d) -> $v<'a, $($g),*> {
+                let mut d = Box::new(data);
+                let v = unsafe { ::std::mem::transmute(f(&mut *d)) };
+                let ptr = unsafe { ::std::ptr::NonNull::new_unchecked(Box::into_raw(d)) };
+
+                Box::pin($name { data: ptr, view: Some(v), _pin: ::std::marker::PhantomPinned })
+            }
+
+            pub fn new_result<E,F>(data: $d,
+                                   f: F)
+                                   -> ::std::result::Result<::std::pin::Pin<Box<$name<$($g),*>>>,($d,E)>
+                                   where F: for<'a> FnOnce(&'a mut $d) -> ::std::result::Result<$v<'a, $($g),*>,E> {
+                let mut d = Box::new(data);
+                match f(&mut *d).map(|v| unsafe { ::std::mem::transmute(v) }) {
+                    Ok(v) => {
+                        let ptr = unsafe { ::std::ptr::NonNull::new_unchecked(Box::into_raw(d)) };
+                        Ok(Box::pin($name { data: ptr, view: Some(v), _pin: ::std::marker::PhantomPinned }))
+                    }
+                    Err(e) => Err((*d,e))
+                }
+            }
+
+            pub fn into_inner(self: ::std::pin::Pin<Box<$name<$($g),*>>>) -> $d {
+                unsafe {
+                    let mut this = ::std::pin::Pin::into_inner_unchecked(self);
+                    this.view.take();
+                    let ptr = this.data;
+                    ::std::mem::forget(this);
+                    *Box::from_raw(ptr.as_ptr())
+                }
+            }
+
+            pub fn view<'a>(self: ::std::pin::Pin<&'a $name<$($g),*>>) -> &'a $v<'a, $($g),*> {
+                unsafe { ::std::mem::transmute(self.get_ref().view.as_ref().unwrap()) }
+            }
+
+            pub fn view_mut<'a>(self: ::std::pin::Pin<&'a mut $name<$($g),*>>) -> &'a mut $v<'a, $($g),*> {
+                unsafe { ::std::mem::transmute(self.get_unchecked_mut().view.as_mut().unwrap()) }
+            }
+
+            /// Re-derives the view over the same live allocation,
+            /// without reparsing or reallocating `data`. `f` only
+            /// ever sees the current view, never `data` itself, so
+            /// it can narrow (e.g. to a sub-node) but not fabricate
+            /// references to data it was never given. The target box
+            /// `B` doesn't have to be `$name` itself -- it can be any
+            /// other non-`shared` `viewbox!`-generated box over the same `$d`, so
+            /// narrowing can land in a box with a different view type.
+            pub fn map_view<B, F>(self: ::std::pin::Pin<Box<$name<$($g),*>>>, f: F)
+                                  -> ::std::pin::Pin<Box<B>>
+                                  where B: $crate::ViewBoxRaw<Data = $d>,
+                                        F: for<'a> FnOnce($v<'a, $($g),*>) -> B::View<'a> {
+                unsafe {
+                    let (data, old) = <$name<$($g),*> as $crate::ViewBoxRaw>::__into_raw(self);
+                    B::__from_raw(data, f(old))
+                }
             }
 
-            pub fn view<'a>(&'a self) -> &'a $v<'a> {
-                unsafe { ::std::mem::transmute(&self.view) }
+            /// Fallible counterpart of [`map_view`][Self::map_view].
+            /// Since `f` consumes the view, it must hand the view
+            /// back alongside `E` if it fails, so the original box
+            /// can be reconstituted for the caller.
+            pub fn try_map_view<B, E, F>(self: ::std::pin::Pin<Box<$name<$($g),*>>>, f: F)
+                                        -> ::std::result::Result<::std::pin::Pin<Box<B>>,
+                                                                  (::std::pin::Pin<Box<$name<$($g),*>>>,E)>
+                                        where B: $crate::ViewBoxRaw<Data = $d>,
+                                              F: for<'a> FnOnce($v<'a, $($g),*>)
+                                                 -> ::std::result::Result<B::View<'a>,($v<'a, $($g),*>,E)> {
+                unsafe {
+                    let (data, old) = <$name<$($g),*> as $crate::ViewBoxRaw>::__into_raw(self);
+                    match f(old) {
+                        Ok(new) => Ok(B::__from_raw(data, new)),
+                        Err((old,e)) => Err((<$name<$($g),*> as $crate::ViewBoxRaw>::__from_raw(data, old), e))
+                    }
+                }
             }
 
-            pub fn view_mut<'a>(&'a mut self) -> &'a mut $v<'a> {
-                unsafe { ::std::mem::transmute(&mut self.view) }
+            /// Read-only access to the backing data, without having
+            /// to `into_inner` the box (and thus give up the view).
+            pub fn with_owner<R>(&self, f: impl FnOnce(&$d) -> R) -> R {
+                f(unsafe { self.data.as_ref() })
             }
         }
     );
     (#[derive(PartialEq $(,$derive:ident)*)] struct $name:ident<$d:ty, $v:ident>;) => (
         impl ::std::cmp::PartialEq for $name {
-            fn eq(&self, other: &$name) -> bool { self.view() == other.view(); }
+            fn eq(&self, other: &$name) -> bool {
+                let a = unsafe { ::std::pin::Pin::new_unchecked(self) };
+                let b = unsafe { ::std::pin::Pin::new_unchecked(other) };
+                a.view() == b.view()
+            }
         }
-        viewbox!(struct $name<$d,$v>;)
+        viewbox!(struct $name<$d,$v>;);
     );
     (#[derive(Debug $(,$derive:ident)*)] struct $name:ident<$d:ty, $v:ident>;) => (
         impl ::std::fmt::Debug for $name {
             fn fmt(&self, fmt: &mut ::std::fmt::Formatter)
                    -> ::std::result::Result<(), ::std::fmt::FormatError> {
-                self.view().fmt(fmt)
+                let this = unsafe { ::std::pin::Pin::new_unchecked(self) };
+                this.view().fmt(fmt)
+            }
+        }
+        viewbox!(struct $name<$d,$v>;);
+    );
+    (#[derive(Drop $(,$derive:ident)*)] struct $name:ident<$d:ty, $v:ident>;) => (
+        // Opt out of the `Option<$v<'static>>` indirection: the
+        // caller is asserting that `$v` has no `Drop` impl of its
+        // own, so field-drop order can never be observed and the
+        // plain layout (whose `Drop` impl below frees `data` before
+        // the `view` field itself is dropped) is sound.
+        pub struct $name {
+            view: $v<'static>,
+            #[allow(dead_code)]
+            data: ::std::ptr::NonNull<$d>,
+            _pin: ::std::marker::PhantomPinned
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { drop(Box::from_raw(self.data.as_ptr())); }
+            }
+        }
+
+        impl $crate::ViewBoxRaw for $name {
+            type Data = $d;
+            type View<'a> = $v<'a>;
+
+            unsafe fn __from_raw(data: ::std::ptr::NonNull<$d>, view: $v<'static>)
+                                 -> ::std::pin::Pin<Box<$name>> {
+                Box::pin($name { data, view, _pin: ::std::marker::PhantomPinned })
+            }
+
+            unsafe fn __into_raw(self: ::std::pin::Pin<Box<$name>>)
+                                 -> (::std::ptr::NonNull<$d>, $v<'static>) {
+                let this = ::std::pin::Pin::into_inner_unchecked(self);
+                let view = ::std::ptr::read(&this.view);
+                let data = this.data;
+                ::std::mem::forget(this);
+                (data, view)
+            }
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            pub fn new<F>(data: $d, f: F) -> ::std::pin::Pin<Box<$name>>
+                          where F: for<'a> FnOnce(&'a mut $d) -> $v<'a> {
+                let mut d = Box::new(data);
+                let v = unsafe { ::std::mem::transmute(f(&mut *d)) };
+                let ptr = unsafe { ::std::ptr::NonNull::new_unchecked(Box::into_raw(d)) };
+
+                Box::pin($name { data: ptr, view: v, _pin: ::std::marker::PhantomPinned })
+            }
+
+            pub fn new_result<E,F>(data: $d,
+                                   f: F)
+                                   -> ::std::result::Result<::std::pin::Pin<Box<$name>>,($d,E)>
+                                   where F: for<'a> FnOnce(&'a mut $d) -> ::std::result::Result<$v<'a>,E> {
+                let mut d = Box::new(data);
+                match f(&mut *d).map(|v| unsafe { ::std::mem::transmute(v) }) {
+                    Ok(v) => {
+                        let ptr = unsafe { ::std::ptr::NonNull::new_unchecked(Box::into_raw(d)) };
+                        Ok(Box::pin($name { data: ptr, view: v, _pin: ::std::marker::PhantomPinned }))
+                    }
+                    Err(e) => Err((*d,e))
+                }
+            }
+
+            pub fn into_inner(self: ::std::pin::Pin<Box<$name>>) -> $d {
+                unsafe {
+                    let this = ::std::pin::Pin::into_inner_unchecked(self);
+                    let ptr = this.data;
+                    ::std::mem::forget(this);
+                    *Box::from_raw(ptr.as_ptr())
+                }
+            }
+
+            pub fn view<'a>(self: ::std::pin::Pin<&'a $name>) -> &'a $v<'a> {
+                unsafe { ::std::mem::transmute(&self.get_ref().view) }
+            }
+
+            pub fn view_mut<'a>(self: ::std::pin::Pin<&'a mut $name>) -> &'a mut $v<'a> {
+                unsafe { ::std::mem::transmute(&mut self.get_unchecked_mut().view) }
+            }
+
+            /// Re-derives the view over the same live allocation,
+            /// without reparsing or reallocating `data`. The target
+            /// box `B` doesn't have to be `$name` itself -- it can be
+            /// any other non-`shared` `viewbox!`-generated box over the same `$d`,
+            /// so narrowing can land in a box with a different view
+            /// type. As in the `Option`-backed arm, the raw
+            /// conversion into `(data, view)` fully consumes `self`
+            /// before `f` runs, so there's nothing left for a
+            /// panicking `f` to double-drop.
+            pub fn map_view<B, F>(self: ::std::pin::Pin<Box<$name>>, f: F)
+                                  -> ::std::pin::Pin<Box<B>>
+                                  where B: $crate::ViewBoxRaw<Data = $d>,
+                                        F: for<'a> FnOnce($v<'a>) -> B::View<'a> {
+                unsafe {
+                    let (data, old) = <$name as $crate::ViewBoxRaw>::__into_raw(self);
+                    B::__from_raw(data, f(old))
+                }
+            }
+
+            /// Fallible counterpart of [`map_view`][Self::map_view].
+            /// Since `f` consumes the view, it must hand the view
+            /// back alongside `E` if it fails, so the original box
+            /// can be reconstituted for the caller.
+            pub fn try_map_view<B, E, F>(self: ::std::pin::Pin<Box<$name>>, f: F)
+                                        -> ::std::result::Result<::std::pin::Pin<Box<B>>,
+                                                                  (::std::pin::Pin<Box<$name>>,E)>
+                                        where B: $crate::ViewBoxRaw<Data = $d>,
+                                              F: for<'a> FnOnce($v<'a>)
+                                                 -> ::std::result::Result<B::View<'a>,($v<'a>,E)> {
+                unsafe {
+                    let (data, old) = <$name as $crate::ViewBoxRaw>::__into_raw(self);
+                    match f(old) {
+                        Ok(new) => Ok(B::__from_raw(data, new)),
+                        Err((old,e)) => Err((<$name as $crate::ViewBoxRaw>::__from_raw(data, old), e))
+                    }
+                }
+            }
+
+            /// Read-only access to the backing data, without having
+            /// to `into_inner` the box (and thus give up the view).
+            pub fn with_owner<R>(&self, f: impl FnOnce(&$d) -> R) -> R {
+                f(unsafe { self.data.as_ref() })
             }
         }
-        viewbox!(struct $name<$d,$v>;)
     );
     (#[derive()] struct $name:ident<$d:ty, $v:ident>;) => (
-        viewbox!(struct $name<$d,$v>;)
+        viewbox!(struct $name<$d,$v>;);
+    );
+    (shared struct $name:ident<$d:ty, $v:ident>;) => (
+        pub struct $name {
+            view: Option<$v<'static>>,
+            #[allow(dead_code)]
+            data: ::std::sync::Arc<$d>,
+            _pin: ::std::marker::PhantomPinned
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                // The known `Arc::drop` hazard: once the strong count
+                // is decremented, another thread can free the
+                // `ArcInner` out from under any reference still
+                // pointing into it. So the view (which holds exactly
+                // such references) must be torn down first; `data`
+                // is never touched again afterwards.
+                self.view.take();
+            }
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            pub fn new<F>(data: $d, f: F) -> ::std::pin::Pin<Box<$name>>
+                          where F: for<'a> FnOnce(&'a mut $d) -> $v<'a> {
+                let mut d = ::std::sync::Arc::new(data);
+                let v = unsafe { ::std::mem::transmute(f(::std::sync::Arc::get_mut(&mut d).unwrap())) };
+
+                Box::pin($name { data: d, view: Some(v), _pin: ::std::marker::PhantomPinned })
+            }
+
+            pub fn new_result<E,F>(data: $d,
+                                   f: F)
+                                   -> ::std::result::Result<::std::pin::Pin<Box<$name>>,($d,E)>
+                                   where F: for<'a> FnOnce(&'a mut $d) -> ::std::result::Result<$v<'a>,E> {
+                let mut d = ::std::sync::Arc::new(data);
+                match f(::std::sync::Arc::get_mut(&mut d).unwrap()).map(|v| unsafe { ::std::mem::transmute(v) }) {
+                    Ok(v) => Ok(Box::pin($name { data: d, view: Some(v), _pin: ::std::marker::PhantomPinned })),
+                    Err(e) => Err((::std::sync::Arc::try_unwrap(d).ok().unwrap(),e))
+                }
+            }
+
+            /// Reclaims `data` if this is the only remaining owner,
+            /// handing the box back unchanged otherwise.
+            pub fn into_inner(mut self: ::std::pin::Pin<Box<$name>>)
+                              -> ::std::result::Result<$d, ::std::pin::Pin<Box<$name>>> {
+                let this = unsafe { self.as_mut().get_unchecked_mut() };
+                if ::std::sync::Arc::strong_count(&this.data) == 1 {
+                    // We're the last owner, so the view can't be
+                    // observed by anyone else; drop it before
+                    // reclaiming `data` below.
+                    this.view.take();
+                    let data = unsafe { ::std::ptr::read(&this.data) };
+                    let d = ::std::sync::Arc::try_unwrap(data).ok().unwrap();
+                    unsafe { ::std::mem::forget(::std::pin::Pin::into_inner_unchecked(self)); }
+                    Ok(d)
+                } else {
+                    Err(self)
+                }
+            }
+
+            pub fn view<'a>(self: ::std::pin::Pin<&'a $name>) -> &'a $v<'a> {
+                unsafe { ::std::mem::transmute(self.get_ref().view.as_ref().unwrap()) }
+            }
+
+            pub fn view_mut<'a>(self: ::std::pin::Pin<&'a mut $name>) -> &'a mut $v<'a> {
+                unsafe { ::std::mem::transmute(self.get_unchecked_mut().view.as_mut().unwrap()) }
+            }
+
+            /// Read-only access to the shared backing data.
+            pub fn with_owner<R>(&self, f: impl FnOnce(&$d) -> R) -> R {
+                f(&self.data)
+            }
+
+            /// Cheaply clones the box: the data is shared via the
+            /// `Arc`, and each clone gets its own `view` value
+            /// (cloned from this one) pointing into that same shared
+            /// allocation. Not a `std::clone::Clone` impl, since that
+            /// trait can't express returning a fresh `Pin<Box<Self>>`
+            /// for a `!Unpin` type.
+            pub fn clone(self: ::std::pin::Pin<&$name>) -> ::std::pin::Pin<Box<$name>>
+                         where $v<'static>: Clone {
+                let this = self.get_ref();
+                Box::pin($name {
+                    data: ::std::sync::Arc::clone(&this.data),
+                    view: this.view.clone(),
+                    _pin: ::std::marker::PhantomPinned
+                })
+            }
+        }
     );
 }
 
@@ -72,24 +577,46 @@ macro_rules! viewbox {
 mod test {
     // Test data structure
     #[derive(PartialEq,Debug)]
-    struct TestData {
+    pub struct TestData {
         foo: i32,
         bar: String
     }
-    
+
     // View structure that has references into the boxed data
-    struct TestView<'a> {
+    #[derive(Clone,PartialEq,Debug)]
+    pub struct TestView<'a> {
         x: &'a i32,
         y: &'a str
     }
-    
+
     // Create TestBox which combines TestData and TestView
     viewbox! {
         struct TestBox<TestData,TestView>;
     }
-    
+
+    // EqBox combines TestData and TestView with a derived PartialEq
+    // impl that compares boxes by their view.
+    viewbox! {
+        #[derive(PartialEq)]
+        struct EqBox<TestData,TestView>;
+    }
+
+    #[test]
+    fn eq() {
+        let t1 = TestData { foo: 42, bar: "Hello".to_string() };
+        let t2 = TestData { foo: 42, bar: "Hello".to_string() };
+        let t3 = TestData { foo: 1, bar: "Goodbye".to_string() };
+        let v1 = EqBox::new(t1, |d| TestView { x: &d.foo, y: &d.bar });
+        let v2 = EqBox::new(t2, |d| TestView { x: &d.foo, y: &d.bar });
+        let v3 = EqBox::new(t3, |d| TestView { x: &d.foo, y: &d.bar });
+        // EqBox has no Debug impl, so compare directly rather than
+        // via assert_eq!/assert_ne! (which require Debug).
+        assert!(*v1 == *v2);
+        assert!(*v1 != *v3);
+    }
+
     // Mutable view into TestData
-    struct MutView<'a> {
+    pub struct MutView<'a> {
         x: &'a mut i32,
         y: &'a mut String
     }
@@ -105,10 +632,10 @@ mod test {
         let t = TestData { foo: 42, bar: "Hello".to_string() };
         // Move it into box, creating a view with interior references
         let v = TestBox::new(t, |d| TestView { x: &d.foo, y: &d.bar });
-        
+
         // We can access the boxed data via the view
-        assert_eq!(v.view().x, &42i32);
-        assert_eq!(v.view().y, "Hello");
+        assert_eq!(v.as_ref().view().x, &42i32);
+        assert_eq!(v.as_ref().view().y, "Hello");
         // We can unwrap the view to get the data back
         let t = v.into_inner();
         assert_eq!(t.foo, 42i32);
@@ -119,9 +646,9 @@ mod test {
     fn mutation() {
         let t = TestData { foo: 42, bar: "Hello".to_string() };
         let mut v = MutBox::new(t, |d| MutView { x: &mut d.foo, y: &mut d.bar });
-        
-        *v.view_mut().x = 5;
-        *v.view_mut().y = "Goodbye".to_string();
+
+        *v.as_mut().view_mut().x = 5;
+        *v.as_mut().view_mut().y = "Goodbye".to_string();
         let t = v.into_inner();
         assert_eq!(t, TestData { foo: 5, bar: "Goodbye".to_string() });
     }
@@ -132,4 +659,232 @@ mod test {
         let v = MutBox::new_result(t, |_| Err("Nope")).err().unwrap();
         assert_eq!(v, (TestData { foo: 42, bar: "Hello".to_string() }, "Nope"))
     }
+
+    // View type whose destructor runs code that depends on `data`
+    // still being alive; records drop order into a shared log.
+    pub struct LoggingView<'a> {
+        x: &'a i32,
+        log: &'static ::std::sync::Mutex<Vec<&'static str>>
+    }
+
+    impl<'a> Drop for LoggingView<'a> {
+        fn drop(&mut self) {
+            // If `data` were already gone, reading through `self.x`
+            // here would be observing a dangling reference.
+            assert_eq!(*self.x, 42i32);
+            self.log.lock().unwrap().push("view");
+        }
+    }
+
+    viewbox! {
+        struct LoggingBox<TestData, LoggingView>;
+    }
+
+    #[test]
+    fn drop_safe() {
+        static LOG: ::std::sync::Mutex<Vec<&'static str>> = ::std::sync::Mutex::new(Vec::new());
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        {
+            let _v = LoggingBox::new(t, |d| LoggingView { x: &d.foo, log: &LOG });
+        }
+        assert_eq!(LOG.lock().unwrap().as_slice(), &["view"]);
+    }
+
+    // Reads through `view` and mutates through `view_mut` on the
+    // pinned, NonNull-backed layout. This is a plain test, not a
+    // guarantee: it only catches aliasing violations if the binary
+    // actually runs under Miri (`cargo +nightly miri test`), which
+    // isn't wired up anywhere in this repo yet.
+    #[test]
+    fn pinned_access() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = TestBox::new(t, |d| TestView { x: &d.foo, y: &d.bar });
+        assert_eq!(v.as_ref().view().x, &42i32);
+
+        let t2 = TestData { foo: 1, bar: "a".to_string() };
+        let mut v2 = MutBox::new(t2, |d| MutView { x: &mut d.foo, y: &mut d.bar });
+        *v2.as_mut().view_mut().x += 1;
+        assert_eq!(v2.into_inner().foo, 2i32);
+    }
+
+    #[test]
+    fn map_view() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = TestBox::new(t, |d| TestView { x: &d.foo, y: &d.bar });
+        // Re-derive the view over the same allocation without reparsing.
+        let v = v.map_view::<TestBox, _>(|old| TestView { x: old.x, y: old.y });
+        assert_eq!(v.as_ref().view().x, &42i32);
+        assert_eq!(v.with_owner(|d| d.foo), 42i32);
+    }
+
+    #[test]
+    fn try_map_view() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = TestBox::new(t, |d| TestView { x: &d.foo, y: &d.bar });
+        let v = v.try_map_view::<TestBox, _, _>(|old| {
+            if *old.x == 42 { Ok(TestView { x: old.x, y: old.y }) } else { Err((old, "wrong")) }
+        }).ok().unwrap();
+        assert_eq!(v.as_ref().view().x, &42i32);
+
+        let t2 = TestData { foo: 1, bar: "a".to_string() };
+        let v2 = TestBox::new(t2, |d| TestView { x: &d.foo, y: &d.bar });
+        let (v2, e) = v2.try_map_view::<TestBox, _, _>(|old| Err((old, "nope"))).err().unwrap();
+        assert_eq!(e, "nope");
+        // The box is intact: the original view survived the failed map.
+        assert_eq!(v2.as_ref().view().x, &1i32);
+    }
+
+    // View narrower than `TestView`, exposing only `foo`. Exercises
+    // `map_view`/`try_map_view` narrowing into a *different* box/view
+    // type, not just re-deriving the same one.
+    pub struct FooView<'a> {
+        foo: &'a i32
+    }
+
+    viewbox! {
+        struct FooBox<TestData, FooView>;
+    }
+
+    #[test]
+    fn map_view_cross_type() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = TestBox::new(t, |d| TestView { x: &d.foo, y: &d.bar });
+        // Narrow from a `TestBox`/`TestView` into a `FooBox`/`FooView`
+        // over the same underlying `TestData`, without reallocating.
+        let v = v.map_view::<FooBox, _>(|old| FooView { foo: old.x });
+        assert_eq!(v.as_ref().view().foo, &42i32);
+        assert_eq!(v.with_owner(|d| d.bar.clone()), "Hello".to_string());
+    }
+
+    #[test]
+    fn try_map_view_cross_type() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = TestBox::new(t, |d| TestView { x: &d.foo, y: &d.bar });
+        let v = v.try_map_view::<FooBox, _, _>(|old| {
+            if *old.x == 42 { Ok(FooView { foo: old.x }) } else { Err((old, "wrong")) }
+        }).ok().unwrap();
+        assert_eq!(v.as_ref().view().foo, &42i32);
+
+        let t2 = TestData { foo: 1, bar: "a".to_string() };
+        let v2 = TestBox::new(t2, |d| TestView { x: &d.foo, y: &d.bar });
+        let (v2, e) = v2.try_map_view::<FooBox, _, _>(|old| Err((old, "nope"))).err().unwrap();
+        assert_eq!(e, "nope");
+        // The box is intact and still a TestBox: the failed map never
+        // touched the original view or box type.
+        assert_eq!(v2.as_ref().view().x, &1i32);
+    }
+
+    // View with no Drop impl of its own, suitable for the
+    // `#[derive(Drop)]` opt-out arm below.
+    pub struct PlainView<'a> {
+        x: &'a i32
+    }
+
+    // PlainBox skips the `Option<$v<'static>>` indirection entirely.
+    viewbox! {
+        #[derive(Drop)]
+        struct PlainBox<TestData, PlainView>;
+    }
+
+    #[test]
+    fn derive_drop_map_view() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = PlainBox::new(t, |d| PlainView { x: &d.foo });
+        let v = v.map_view::<PlainBox, _>(|old| PlainView { x: old.x });
+        assert_eq!(v.as_ref().view().x, &42i32);
+        assert_eq!(v.with_owner(|d| d.foo), 42i32);
+    }
+
+    #[test]
+    fn derive_drop_try_map_view() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = PlainBox::new(t, |d| PlainView { x: &d.foo });
+        let v = v.try_map_view::<PlainBox, _, _>(|old| {
+            if *old.x == 42 { Ok(PlainView { x: old.x }) } else { Err((old, "wrong")) }
+        }).ok().unwrap();
+        assert_eq!(v.as_ref().view().x, &42i32);
+
+        let t2 = TestData { foo: 1, bar: "a".to_string() };
+        let v2 = PlainBox::new(t2, |d| PlainView { x: &d.foo });
+        let (v2, e) = v2.try_map_view::<PlainBox, _, _>(|old| Err((old, "nope"))).err().unwrap();
+        assert_eq!(e, "nope");
+        assert_eq!(v2.as_ref().view().x, &1i32);
+    }
+
+    // SharedBox combines TestData and TestView behind an Arc so
+    // several owners can each hold their own view of the same data.
+    viewbox! {
+        shared struct SharedBox<TestData, TestView>;
+    }
+
+    #[test]
+    fn shared() {
+        let t = TestData { foo: 42, bar: "Hello".to_string() };
+        let v = SharedBox::new(t, |d| TestView { x: &d.foo, y: &d.bar });
+        let v2 = v.as_ref().clone();
+
+        assert_eq!(v.as_ref().view().x, &42i32);
+        assert_eq!(v2.as_ref().view().x, &42i32);
+
+        // Neither clone can reclaim `data` while the other is alive.
+        let v = match v.into_inner() { Ok(_) => panic!("expected Err"), Err(v) => v };
+        drop(v2);
+        // Now the sole owner: reclaiming `data` succeeds.
+        assert_eq!(v.into_inner().ok().unwrap().foo, 42i32);
+    }
+
+    // View generic over the element type of the `Vec<T>` it borrows.
+    pub struct SliceView<'a, T> {
+        items: &'a [T]
+    }
+
+    // VecBox viewboxes a `Vec<T>` for any `T`, not just a fixed type.
+    // `T: 'static` is required because the view is stored internally
+    // as `SliceView<'static, T>`, which itself requires `T: 'static`.
+    viewbox! {
+        struct VecBox<T; Vec<T>, SliceView> where T: 'static;
+    }
+
+    #[test]
+    fn generic() {
+        let v = VecBox::new(vec![1,2,3], |d| SliceView { items: &d[..] });
+        assert_eq!(v.as_ref().view().items, &[1,2,3]);
+        assert_eq!(v.into_inner(), vec![1,2,3]);
+    }
+
+    #[test]
+    fn generic_map_view() {
+        // map_view/try_map_view parity with the non-generic arms.
+        let v = VecBox::new(vec![1,2,3], |d| SliceView { items: &d[..] });
+        let v = v.map_view::<VecBox<i32>, _>(|old| SliceView { items: &old.items[1..] });
+        assert_eq!(v.as_ref().view().items, &[2,3]);
+
+        let v2 = VecBox::new(vec![1,2,3], |d| SliceView { items: &d[..] });
+        let (v2, e) = v2.try_map_view::<VecBox<i32>, _, _>(|old| Err((old, "nope"))).err().unwrap();
+        assert_eq!(e, "nope");
+        assert_eq!(v2.as_ref().view().items, &[1,2,3]);
+    }
+
+    // View whose generic list starts with a lifetime rather than a
+    // type param, e.g. a borrowed environment the data itself came
+    // from. Exercises the `$($g:tt),*` list when its first token is
+    // a lifetime, not just a type -- this is the case that used to
+    // hit `$d:ty`'s hard parse failure in the plain 2-param arm.
+    pub struct EnvView<'a, 'env, T> {
+        items: &'env [T],
+        _owner: ::std::marker::PhantomData<&'a ()>
+    }
+
+    // EnvBox viewboxes a `&'env [T]` borrowed from the caller, rather
+    // than owning its data outright.
+    viewbox! {
+        struct EnvBox<'env, T; &'env [T], EnvView> where T: 'static;
+    }
+
+    #[test]
+    fn generic_lifetime() {
+        let data: &'static [i32] = &[1,2,3];
+        let v = EnvBox::new(data, |d| EnvView { items: *d, _owner: ::std::marker::PhantomData });
+        assert_eq!(v.as_ref().view().items, &[1,2,3]);
+    }
 }